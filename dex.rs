@@ -1,25 +1,98 @@
 #![cfg(feature = "program")]
 
+// Crate features this file checks. No manifest ships with the source, so the
+// Cargo.toml that builds it has to list them under `[features]`:
+// - `program` (default): builds the program; without it the crate is empty
+// - `no-entrypoint`: leaves out the entrypoint for crates that CPI into the DEX
+// - `custom-panic`: swaps `entrypoint!`'s default panic handler for `custom_panic`
+// - `custom-heap`: checked by `entrypoint!` too; never enabled, the DEX keeps
+//   the default allocator
+// - `program-id-override`: reads the program ID from `DEX_PROGRAM_ID`
+// - `verbose-logs`, `debug-logs`: extra logging, see below
+
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    entrypoint::{Entry, EntryPoint, ProgramResult},
-    entrypoint::ProgramResult::{InvalidArgument, Success},
+    entrypoint::ProgramResult,
     msg,
+    program::{invoke_signed, set_return_data},
     program_error::ProgramError,
     pubkey::Pubkey,
-    program_pack::{Pack, IsInitialized},
+    program_pack::{Pack, IsInitialized, Sealed},
     sysvar::{Sysvar},
     rent::Rent,
     system_program,
-    clock::{self, UnixTimestamp},
-    spl_token::{self, instruction::{transfer}, state::{Account}},
+    clock::{self, Clock, UnixTimestamp},
+    spl_token::{self, instruction::{transfer_checked}, state::{Account, Mint}},
 };
-use std::mem::size_of;
 use num_enum::TryFromPrimitive;
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/**
+ * Logging
+ */
+// Formatting log lines costs compute units on every call, so anything beyond
+// errors is compiled out unless the `verbose-logs` feature is enabled
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        msg!($($arg)*)
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "verbose-logs") {
+            msg!($($arg)*);
+        }
+    };
+}
+
+// One JSON object per state transition for devnet debugging, e.g.
+// `log_json!("trade_created", trade = trade_account.key, maker_amount = params.maker_amount)`.
+// Values are emitted as escaped strings so u64 amounts survive JSON parsers
+// intact and any Display output still makes a valid line.
+macro_rules! log_json {
+    ($event:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        if cfg!(feature = "debug-logs") {
+            let mut line = format!("{{\"event\":\"{}\"", json_escape(&$event.to_string()));
+            $(
+                line.push_str(&format!(
+                    ",\"{}\":\"{}\"",
+                    stringify!($key),
+                    json_escape(&$value.to_string()),
+                ));
+            )*
+            line.push('}');
+            msg!("{}", line);
+        }
+    };
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
 
 // Generate program ID in `Solana-keygen new` format
+#[cfg(not(feature = "program-id-override"))]
 solana_program::declare_id!("DEXprojBt4Rv7Gh5z623Yf7fyTNzgJ123JzNnmCQ8Fr");
 
+// Devnet/localnet deployments build with `program-id-override` and set
+// `DEX_PROGRAM_ID` to their own keypair's pubkey at compile time. The ID is
+// decoded in a const, so a bad value fails the build rather than the program.
+#[cfg(feature = "program-id-override")]
+solana_program::declare_id!(env!("DEX_PROGRAM_ID"));
+
 /**
  * Error definitions
  */
@@ -30,6 +103,14 @@ pub enum DexError {
     TradeAlreadyExist = 1,
     TradeNotFound = 2,
     InsufficientFunds = 3,
+    MintMismatch = 4,
+    UnsupportedInstructionVersion = 5,
+    InvalidStatusTransition = 6,
+    TradeExpired = 7,
+    MissingRequiredSignatureMaker = 8,
+    UnsupportedTradeLayout = 9,
+    InvalidExpiry = 10,
+    MissingRequiredSignatureTaker = 11,
 }
 
 impl From<DexError> for ProgramError {
@@ -44,108 +125,2431 @@ impl From<DexError> for ProgramError {
 const MAX_TRADES_SIZE: usize = 1024;
 const SIGNER_SEED: &[&[u8]] = &[b"solana", b"dex"];
 const MINIMUM_TRADE_AMOUNT: u64 = 100;
+// Optional reference hash (e.g. an off-chain invoice id), all zeroes when unset
+const MEMO_LEN: usize = 32;
+// Payment mints a maker accepts in addition to `taker_token_pubkey`
+const MAX_ALTERNATIVE_PAYMENTS: usize = 3;
 
 /**
  * DEX trade data structure
  */
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, BorshSerialize, BorshDeserialize)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum TradeStatus {
+    Uninitialized = 0,
+    Open = 1,
+    PartiallyFilled = 2,
+    Filled = 3,
+    Cancelled = 4,
+    Expired = 5,
+}
+
+impl TradeStatus {
+    // Every handler that changes a trade goes through `Trade::set_status`,
+    // repricing included: an open trade moves to its own status
+    pub fn can_transition_to(self, next: TradeStatus) -> bool {
+        use TradeStatus::*;
+        matches!(
+            (self, next),
+            (Uninitialized, Open)
+                | (Open, Open)
+                | (Open | PartiallyFilled, PartiallyFilled | Filled | Cancelled | Expired)
+        )
+    }
+}
+
+// Bots prefer slot horizons since they don't depend on validator clock drift
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, BorshSerialize, BorshDeserialize)]
+#[borsh(use_discriminant = true)]
+#[repr(u8)]
+pub enum ExpiryKind {
+    Never = 0,
+    UnixTimestamp = 1,
+    Slot = 2,
+}
+
+// Another mint the maker accepts as payment, at its own amount
+#[derive(Debug, Clone, Copy, PartialEq, Default, BorshSerialize, BorshDeserialize)]
+pub struct PaymentOption {
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub decimals: u8,
+}
+
+const PAYMENT_OPTION_LEN: usize = 32 + 8 + 1;
+
+// Trade accounts are Borsh-encoded field by field, in declaration order:
+//
+//   offset  size  field
+//        0     1  status (TradeStatus)
+//        1     1  layout_version
+//        2    32  maker_pubkey
+//       34     8  taker_amount
+//       42     8  maker_amount
+//       50    32  taker_token_pubkey
+//       82    32  maker_token_pubkey
+//      114     1  taker_decimals
+//      115     1  maker_decimals
+//      116     8  trade_timestamp
+//      124    32  memo
+//      156     1  expiry_kind (ExpiryKind)
+//      157     8  expiry
+//      165     1  alternative_payment_count
+//      166   123  alternative_payments (3 x mint, amount, decimals)
+//      289    32  escrow_pubkey
+//
+// `status` stays at offset 0 in every layout so clients can filter trades by
+// status with a memcmp.
+pub const TRADE_STATUS_OFFSET: usize = 0;
+pub const TRADE_LAYOUT_VERSION_OFFSET: usize = 1;
+pub const TRADE_LAYOUT_VERSION: u8 = 1;
+
+// Builds before the layout version existed stored a raw copy of a 120-byte
+// struct, with the field order rustc picked for the deployed program:
+//
+//   offset  size  field
+//        0    32  maker_pubkey
+//       32    32  taker_token_pubkey
+//       64    32  maker_token_pubkey
+//       96     8  taker_amount
+//      104     8  maker_amount
+//      112     8  trade_timestamp
+//
+// There's no status, decimals, expiry or escrow key; a trade is open while
+// its maker is set. These accounts are decoded into a `Trade` with
+// `layout_version` 0 so they can still be filled or cancelled.
+const LEGACY_TRADE_LEN: usize = 120;
+const LEGACY_TRADE_LAYOUT_VERSION: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, BorshSerialize, BorshDeserialize)]
 pub struct Trade {
+    status: TradeStatus,
+    layout_version: u8,
     pub maker_pubkey: Pubkey,
     pub taker_amount: u64,
     pub maker_amount: u64,
     pub taker_token_pubkey: Pubkey,
     pub maker_token_pubkey: Pubkey,
+    pub taker_decimals: u8,
+    pub maker_decimals: u8,
     pub trade_timestamp: UnixTimestamp,
+    pub memo: [u8; MEMO_LEN],
+    expiry_kind: ExpiryKind,
+    pub expiry: u64,
+    alternative_payment_count: u8,
+    alternative_payments: [PaymentOption; MAX_ALTERNATIVE_PAYMENTS],
+    // The token account holding this trade's `maker_amount`
+    pub escrow_pubkey: Pubkey,
+}
+
+// Everything a maker fixes when opening a trade; decimals come from the mint
+// accounts, never from instruction data
+pub struct TradeTerms {
+    pub maker_pubkey: Pubkey,
+    pub taker_amount: u64,
+    pub maker_amount: u64,
+    pub taker_token_pubkey: Pubkey,
+    pub maker_token_pubkey: Pubkey,
+    pub taker_decimals: u8,
+    pub maker_decimals: u8,
+    pub trade_timestamp: UnixTimestamp,
+    pub memo: [u8; MEMO_LEN],
+    pub expiry_kind: ExpiryKind,
+    pub expiry: u64,
+    pub escrow_pubkey: Pubkey,
 }
 
 impl Trade {
-    pub fn new(
-        maker_pubkey: Pubkey,
-        taker_amount: u64,
-        maker_amount: u64,
-        taker_token_pubkey: Pubkey,
-        maker_token_pubkey: Pubkey,
-        trade_timestamp: UnixTimestamp,
-    ) -> Self {
+    /// A trade with `terms` that isn't open yet; callers open it with
+    /// `set_status` like any other transition
+    pub fn new(terms: TradeTerms) -> Self {
+        Self {
+            status: TradeStatus::Uninitialized,
+            layout_version: TRADE_LAYOUT_VERSION,
+            maker_pubkey: terms.maker_pubkey,
+            taker_amount: terms.taker_amount,
+            maker_amount: terms.maker_amount,
+            taker_token_pubkey: terms.taker_token_pubkey,
+            maker_token_pubkey: terms.maker_token_pubkey,
+            taker_decimals: terms.taker_decimals,
+            maker_decimals: terms.maker_decimals,
+            trade_timestamp: terms.trade_timestamp,
+            memo: terms.memo,
+            expiry_kind: terms.expiry_kind,
+            expiry: terms.expiry,
+            alternative_payment_count: 0,
+            alternative_payments: [PaymentOption::default(); MAX_ALTERNATIVE_PAYMENTS],
+            escrow_pubkey: terms.escrow_pubkey,
+        }
+    }
+
+    /// Decodes a trade account in either the current or the legacy layout
+    pub fn load(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.len() == LEGACY_TRADE_LEN {
+            return Ok(Self::load_legacy(data));
+        }
+        Self::unpack_unchecked(data)
+    }
+
+    fn load_legacy(data: &[u8]) -> Self {
+        let pubkey = |offset: usize| Pubkey::new_from_array(data[offset..offset + 32].try_into().unwrap());
+        let word = |offset: usize| -> [u8; 8] { data[offset..offset + 8].try_into().unwrap() };
+        let maker_pubkey = pubkey(0);
         Self {
+            status: if maker_pubkey == Pubkey::default() {
+                TradeStatus::Uninitialized
+            } else {
+                TradeStatus::Open
+            },
+            layout_version: LEGACY_TRADE_LAYOUT_VERSION,
             maker_pubkey,
-            taker_amount,
-            maker_amount,
-            taker_token_pubkey,
-            maker_token_pubkey,
-            trade_timestamp
+            taker_token_pubkey: pubkey(32),
+            maker_token_pubkey: pubkey(64),
+            taker_amount: u64::from_le_bytes(word(96)),
+            maker_amount: u64::from_le_bytes(word(104)),
+            trade_timestamp: UnixTimestamp::from_le_bytes(word(112)),
+            ..Self::default()
         }
     }
+
+    /// Writes a trade back to the account `load` read it from. The legacy
+    /// layout can't hold any status but open, so a legacy trade that's been
+    /// settled is cleared instead, and one that stays open can't be changed.
+    pub fn store(&self, data: &mut [u8]) -> ProgramResult {
+        if data.len() != LEGACY_TRADE_LEN {
+            return Self::pack(*self, data);
+        }
+        if matches!(self.status, TradeStatus::Open | TradeStatus::PartiallyFilled) {
+            log_error!("Legacy trades can only be filled or cancelled");
+            return Err(DexError::UnsupportedTradeLayout.into());
+        }
+        data.fill(0);
+        Ok(())
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.layout_version == LEGACY_TRADE_LAYOUT_VERSION
+    }
+
+    pub fn status(&self) -> TradeStatus {
+        self.status
+    }
+
+    pub fn set_status(&mut self, next: TradeStatus) -> ProgramResult {
+        if !self.status.can_transition_to(next) {
+            return Err(DexError::InvalidStatusTransition.into());
+        }
+        self.status = next;
+        Ok(())
+    }
+
+    pub fn expiry_kind(&self) -> ExpiryKind {
+        self.expiry_kind
+    }
+
+    pub fn set_expiry(&mut self, expiry_kind: ExpiryKind, expiry: u64) {
+        self.expiry_kind = expiry_kind;
+        self.expiry = expiry;
+    }
+
+    pub fn is_expired(&self, clock: &Clock) -> bool {
+        is_past_expiry(self.expiry_kind, self.expiry, clock)
+    }
+
+    pub fn alternative_payments(&self) -> &[PaymentOption] {
+        let count = (self.alternative_payment_count as usize).min(MAX_ALTERNATIVE_PAYMENTS);
+        &self.alternative_payments[..count]
+    }
+
+    pub fn set_alternative_payments(&mut self, payments: &[PaymentOption]) -> ProgramResult {
+        if payments.len() > MAX_ALTERNATIVE_PAYMENTS {
+            return Err(ProgramError::InvalidArgument);
+        }
+        self.alternative_payments = [PaymentOption::default(); MAX_ALTERNATIVE_PAYMENTS];
+        self.alternative_payments[..payments.len()].copy_from_slice(payments);
+        self.alternative_payment_count = payments.len() as u8;
+        Ok(())
+    }
+
+    /// Amount and decimals the taker owes when paying in `mint`
+    pub fn payment_for_mint(&self, mint: &Pubkey) -> Option<PaymentOption> {
+        if *mint == self.taker_token_pubkey {
+            return Some(PaymentOption {
+                mint: self.taker_token_pubkey,
+                amount: self.taker_amount,
+                decimals: self.taker_decimals,
+            });
+        }
+        self.alternative_payments().iter().find(|payment| payment.mint == *mint).copied()
+    }
+
+    /// Price of the maker token in taker tokens
+    pub fn price(&self) -> Option<price::Price> {
+        price::Price::from_amounts(
+            self.maker_amount,
+            self.maker_decimals,
+            self.taker_amount,
+            self.taker_decimals,
+            math::Rounding::Down,
+        )
+    }
+}
+
+// A unix expiry past i64::MAX can't be reached, and casting it would wrap it
+// into the past; check_expiry rejects those before they're stored
+fn is_past_expiry(expiry_kind: ExpiryKind, expiry: u64, clock: &Clock) -> bool {
+    match expiry_kind {
+        ExpiryKind::Never => false,
+        ExpiryKind::UnixTimestamp => UnixTimestamp::try_from(expiry)
+            .is_ok_and(|expiry| clock.unix_timestamp >= expiry),
+        ExpiryKind::Slot => clock.slot >= expiry,
+    }
 }
 
+// Validates an expiry before it's written to a trade
+fn check_expiry(expiry_kind: ExpiryKind, expiry: u64, clock: &Clock) -> ProgramResult {
+    if expiry_kind == ExpiryKind::UnixTimestamp && UnixTimestamp::try_from(expiry).is_err() {
+        return Err(DexError::InvalidExpiry.into());
+    }
+    if is_past_expiry(expiry_kind, expiry, clock) {
+        return Err(DexError::TradeExpired.into());
+    }
+    Ok(())
+}
+
+impl Sealed for Trade {}
+
 impl Pack for Trade {
-    const LEN: usize = size_of::<Trade>();
+    const LEN: usize = 166 + MAX_ALTERNATIVE_PAYMENTS * PAYMENT_OPTION_LEN + 32;
 
     fn pack_into_slice(&self, output: &mut [u8]) {
-        let data = self.as_ref();
-        output.copy_from_slice(data);
+        let mut writer = output;
+        // `Pack::pack` has already checked the slice is exactly `LEN` bytes
+        self.serialize(&mut writer).unwrap();
     }
 
     fn unpack_from_slice(input: &[u8]) -> Result<Self, ProgramError> {
-        if input.len() != size_of::<Trade>() {
-            return Err(ProgramError::InvalidArgument);
+        // A freshly allocated account is all zeroes
+        if input[TRADE_STATUS_OFFSET] == TradeStatus::Uninitialized as u8 {
+            return Ok(Self::default());
         }
-        let trade = unsafe { &*(input.as_ptr() as *const Trade) };
-        Ok(*trade)
+        if input[TRADE_LAYOUT_VERSION_OFFSET] != TRADE_LAYOUT_VERSION {
+            return Err(DexError::UnsupportedTradeLayout.into());
+        }
+        Self::try_from_slice(input).map_err(|_| ProgramError::InvalidAccountData)
     }
 }
 
 impl IsInitialized for Trade {
     fn is_initialized(&self) -> bool {
-        self.maker_pubkey != Pubkey::default()
+        self.status != TradeStatus::Uninitialized
     }
 }
 
 impl Default for Trade {
     fn default() -> Self {
         Self {
+            status: TradeStatus::Uninitialized,
+            layout_version: TRADE_LAYOUT_VERSION,
             maker_pubkey: Pubkey::default(),
             taker_amount: 0,
             maker_amount: 0,
             taker_token_pubkey: Pubkey::default(),
             maker_token_pubkey: Pubkey::default(),
+            taker_decimals: 0,
+            maker_decimals: 0,
             trade_timestamp: 0,
+            memo: [0; MEMO_LEN],
+            expiry_kind: ExpiryKind::Never,
+            expiry: 0,
+            alternative_payment_count: 0,
+            alternative_payments: [PaymentOption::default(); MAX_ALTERNATIVE_PAYMENTS],
+            escrow_pubkey: Pubkey::default(),
+        }
+    }
+}
+
+/**
+ * Fixed-point math
+ */
+pub mod math {
+    /// Direction to round a result that isn't exact. Callers pick the
+    /// direction that favors the program: round down what is paid out and
+    /// round up what is owed.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Rounding {
+        Down,
+        Up,
+    }
+
+    /// `a * b / denominator` with a 256-bit intermediate, so only a quotient
+    /// that doesn't fit in u128 overflows
+    pub fn mul_div(a: u128, b: u128, denominator: u128, rounding: Rounding) -> Option<u128> {
+        let (high, low) = full_mul(a, b);
+        let (quotient, remainder) = div_wide(high, low, denominator)?;
+        round_quotient(quotient, remainder, rounding)
+    }
+
+    fn round_quotient(quotient: u128, remainder: u128, rounding: Rounding) -> Option<u128> {
+        if rounding == Rounding::Up && remainder != 0 {
+            quotient.checked_add(1)
+        } else {
+            Some(quotient)
+        }
+    }
+
+    /// Integer square root
+    pub fn sqrt(value: u128, rounding: Rounding) -> u128 {
+        let root = sqrt_floor(value);
+        // The root of a u128 is below 2^64, so neither step can overflow
+        if rounding == Rounding::Up && root * root != value {
+            root + 1
+        } else {
+            root
+        }
+    }
+
+    fn sqrt_floor(value: u128) -> u128 {
+        if value < 2 {
+            return value;
+        }
+        let mut x = value;
+        let mut y = (x >> 1) + (x & 1);
+        while y < x {
+            x = y;
+            y = (x + value / x) / 2;
+        }
+        x
+    }
+
+    const FRAC_BITS: u32 = 64;
+    const FRAC_MASK: u128 = (1 << FRAC_BITS) - 1;
+
+    /// Divides the 256-bit value `high:low` by `denominator`, returning the
+    /// quotient and remainder, or `None` if the quotient needs more than 128
+    /// bits
+    fn div_wide(high: u128, low: u128, denominator: u128) -> Option<(u128, u128)> {
+        if denominator == 0 || high >= denominator {
+            return None;
+        }
+        if high == 0 {
+            return Some((low / denominator, low % denominator));
+        }
+        // Shift-subtract long division; `remainder < denominator` holds at the
+        // top of every step, so the bit shifted out is the only overflow
+        let mut remainder = high;
+        let mut quotient: u128 = 0;
+        for bit in (0..128).rev() {
+            let carry = remainder >> 127 == 1;
+            remainder = (remainder << 1) | ((low >> bit) & 1);
+            quotient <<= 1;
+            if carry || remainder >= denominator {
+                remainder = remainder.wrapping_sub(denominator);
+                quotient |= 1;
+            }
+        }
+        Some((quotient, remainder))
+    }
+
+    /// Full 256-bit product of two u128 values as (high, low) halves
+    fn full_mul(a: u128, b: u128) -> (u128, u128) {
+        let (a_hi, a_lo) = (a >> FRAC_BITS, a & FRAC_MASK);
+        let (b_hi, b_lo) = (b >> FRAC_BITS, b & FRAC_MASK);
+        let lo_lo = a_lo * b_lo;
+        let hi_lo = a_hi * b_lo;
+        let lo_hi = a_lo * b_hi;
+        let hi_hi = a_hi * b_hi;
+        let middle = (lo_lo >> FRAC_BITS) + (hi_lo & FRAC_MASK) + (lo_hi & FRAC_MASK);
+        let low = (middle << FRAC_BITS) | (lo_lo & FRAC_MASK);
+        let high = hi_hi + (hi_lo >> FRAC_BITS) + (lo_hi >> FRAC_BITS) + (middle >> FRAC_BITS);
+        (high, low)
+    }
+
+    /// Unsigned Q64.64 fixed-point number
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+    pub struct Q64(pub u128);
+
+    impl Q64 {
+        pub const ZERO: Q64 = Q64(0);
+        pub const ONE: Q64 = Q64(1 << FRAC_BITS);
+
+        pub fn from_int(value: u64) -> Self {
+            Q64((value as u128) << FRAC_BITS)
+        }
+
+        pub fn from_ratio(numerator: u64, denominator: u64, rounding: Rounding) -> Option<Self> {
+            mul_div(numerator as u128, 1 << FRAC_BITS, denominator as u128, rounding).map(Q64)
+        }
+
+        pub fn floor(&self) -> u64 {
+            (self.0 >> FRAC_BITS) as u64
+        }
+
+        pub fn ceil(&self) -> Option<u64> {
+            let whole = self.floor();
+            if self.0 & FRAC_MASK == 0 {
+                Some(whole)
+            } else {
+                whole.checked_add(1)
+            }
+        }
+
+        pub fn checked_add(&self, other: Q64) -> Option<Self> {
+            self.0.checked_add(other.0).map(Q64)
+        }
+
+        pub fn checked_sub(&self, other: Q64) -> Option<Self> {
+            self.0.checked_sub(other.0).map(Q64)
+        }
+
+        pub fn checked_mul(&self, other: Q64, rounding: Rounding) -> Option<Self> {
+            let (high, low) = full_mul(self.0, other.0);
+            if high >> FRAC_BITS != 0 {
+                return None;
+            }
+            let result = (high << FRAC_BITS) | (low >> FRAC_BITS);
+            if rounding == Rounding::Up && low & FRAC_MASK != 0 {
+                result.checked_add(1).map(Q64)
+            } else {
+                Some(Q64(result))
+            }
+        }
+
+        pub fn checked_div(&self, other: Q64, rounding: Rounding) -> Option<Self> {
+            let (quotient, remainder) = div_wide(self.0 >> FRAC_BITS, self.0 << FRAC_BITS, other.0)?;
+            round_quotient(quotient, remainder, rounding).map(Q64)
+        }
+
+        /// Integer power by repeated squaring; every step rounds the same way
+        pub fn checked_pow(&self, mut exponent: u32, rounding: Rounding) -> Option<Self> {
+            let mut base = *self;
+            let mut result = Q64::ONE;
+            while exponent > 0 {
+                if exponent & 1 == 1 {
+                    result = result.checked_mul(base, rounding)?;
+                }
+                exponent >>= 1;
+                if exponent > 0 {
+                    base = base.checked_mul(base, rounding)?;
+                }
+            }
+            Some(result)
+        }
+
+        /// Square root. The raw value is scaled up by the largest even shift
+        /// that fits, so values above 2^64 lose `(64 - shift) / 2` low bits;
+        /// `Up` is then the next step above `Down` rather than the exact ceiling,
+        /// but never below the true root.
+        pub fn sqrt(&self, rounding: Rounding) -> Self {
+            let shift = (self.0.leading_zeros() & !1).min(FRAC_BITS);
+            let unscaled = (FRAC_BITS - shift) / 2;
+            Q64(sqrt(self.0 << shift, rounding) << unscaled)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mul_div_rounds_in_the_requested_direction() {
+            assert_eq!(mul_div(6, 4, 3, Rounding::Down), Some(8));
+            assert_eq!(mul_div(6, 4, 3, Rounding::Up), Some(8));
+            assert_eq!(mul_div(7, 3, 2, Rounding::Down), Some(10));
+            assert_eq!(mul_div(7, 3, 2, Rounding::Up), Some(11));
+            assert_eq!(mul_div(1, 1, 3, Rounding::Down), Some(0));
+            assert_eq!(mul_div(1, 1, 3, Rounding::Up), Some(1));
+        }
+
+        #[test]
+        fn mul_div_uses_a_wide_intermediate() {
+            assert_eq!(mul_div(u128::MAX / 2, 4, 8, Rounding::Down), Some((1 << 126) - 1));
+            assert_eq!(mul_div(u128::MAX / 2, 4, 8, Rounding::Up), Some(1 << 126));
+            assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX, Rounding::Down), Some(u128::MAX));
+            assert_eq!(mul_div(u128::MAX, u128::MAX - 1, u128::MAX, Rounding::Up), Some(u128::MAX - 1));
+        }
+
+        #[test]
+        fn mul_div_overflow_and_zero_denominator() {
+            assert_eq!(mul_div(u128::MAX, 2, 1, Rounding::Down), None);
+            assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX - 1, Rounding::Down), None);
+            assert_eq!(mul_div(1, 1, 0, Rounding::Down), None);
+            // 7 * b = 2 * u128::MAX + 1: the quotient fits but rounding it up doesn't
+            let b = 97223533405982418132392744980505203273;
+            assert_eq!(mul_div(7, b, 2, Rounding::Down), Some(u128::MAX));
+            assert_eq!(mul_div(7, b, 2, Rounding::Up), None);
+            assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX - 1, Rounding::Up), None);
+        }
+
+        #[test]
+        fn integer_sqrt_rounds_in_the_requested_direction() {
+            assert_eq!(sqrt(0, Rounding::Up), 0);
+            assert_eq!(sqrt(1, Rounding::Up), 1);
+            assert_eq!(sqrt(99, Rounding::Down), 9);
+            assert_eq!(sqrt(99, Rounding::Up), 10);
+            assert_eq!(sqrt(100, Rounding::Down), 10);
+            assert_eq!(sqrt(100, Rounding::Up), 10);
+            assert_eq!(sqrt(u128::MAX, Rounding::Down), u64::MAX as u128);
+            assert_eq!(sqrt(u128::MAX, Rounding::Up), 1 << 64);
+        }
+
+        #[test]
+        fn q64_mul_rounds_in_the_requested_direction() {
+            let half = Q64::from_ratio(1, 2, Rounding::Down).unwrap();
+            assert_eq!(half.checked_mul(Q64::from_int(6), Rounding::Down), Some(Q64::from_int(3)));
+            assert_eq!(half.checked_mul(Q64::from_int(6), Rounding::Up), Some(Q64::from_int(3)));
+            // 2^-64 * 2^-64 is below one unit in the last place
+            assert_eq!(Q64(1).checked_mul(Q64(1), Rounding::Down), Some(Q64::ZERO));
+            assert_eq!(Q64(1).checked_mul(Q64(1), Rounding::Up), Some(Q64(1)));
+        }
+
+        #[test]
+        fn q64_mul_boundaries() {
+            let max_int = Q64::from_int(u64::MAX);
+            assert_eq!(max_int.checked_mul(Q64::ONE, Rounding::Up), Some(max_int));
+            assert_eq!(Q64::from_int(1 << 32).checked_mul(Q64::from_int(1 << 32), Rounding::Down), None);
+            assert_eq!(
+                Q64::from_int(1 << 31).checked_mul(Q64::from_int(1 << 32), Rounding::Down),
+                Some(Q64::from_int(1 << 63))
+            );
+        }
+
+        #[test]
+        fn q64_div_rounds_in_the_requested_direction() {
+            let one = Q64::ONE;
+            let three = Q64::from_int(3);
+            let down = one.checked_div(three, Rounding::Down).unwrap();
+            let up = one.checked_div(three, Rounding::Up).unwrap();
+            assert_eq!(up.0, down.0 + 1);
+            assert_eq!(down, Q64::from_ratio(1, 3, Rounding::Down).unwrap());
+            assert_eq!(Q64::from_int(6).checked_div(Q64::from_int(2), Rounding::Up), Some(three));
+        }
+
+        #[test]
+        fn q64_div_boundaries() {
+            assert_eq!(Q64::ONE.checked_div(Q64::ZERO, Rounding::Down), None);
+            assert_eq!(Q64::from_int(u64::MAX).checked_div(Q64(1), Rounding::Down), None);
+            assert_eq!(Q64::from_int(1).checked_div(Q64(2), Rounding::Down), Some(Q64::from_int(1 << 63)));
+            let seventh = Q64::from_ratio(1, 7, Rounding::Down).unwrap();
+            assert_eq!(Q64::from_int(1000).checked_div(seventh, Rounding::Down).unwrap().floor(), 7000);
+            // Dividing by a value with a large integer part takes the wide path
+            let big = Q64(u128::MAX);
+            assert_eq!(big.checked_div(big, Rounding::Down), Some(Q64::ONE));
+        }
+
+        #[test]
+        fn q64_pow_rounds_in_the_requested_direction() {
+            let two = Q64::from_int(2);
+            assert_eq!(two.checked_pow(0, Rounding::Down), Some(Q64::ONE));
+            assert_eq!(two.checked_pow(10, Rounding::Down), Some(Q64::from_int(1024)));
+            assert_eq!(two.checked_pow(10, Rounding::Up), Some(Q64::from_int(1024)));
+            let third = Q64::from_ratio(1, 3, Rounding::Down).unwrap();
+            let down = third.checked_pow(3, Rounding::Down).unwrap();
+            let up = third.checked_pow(3, Rounding::Up).unwrap();
+            assert!(down < up);
+        }
+
+        #[test]
+        fn q64_pow_boundaries() {
+            let two = Q64::from_int(2);
+            assert_eq!(two.checked_pow(63, Rounding::Down), Some(Q64::from_int(1 << 63)));
+            assert_eq!(two.checked_pow(64, Rounding::Down), None);
+        }
+
+        #[test]
+        fn q64_sqrt_rounds_in_the_requested_direction() {
+            assert_eq!(Q64::from_int(16).sqrt(Rounding::Down), Q64::from_int(4));
+            assert_eq!(Q64::from_int(16).sqrt(Rounding::Up), Q64::from_int(4));
+            let quarter = Q64::from_ratio(1, 4, Rounding::Down).unwrap();
+            let half = Q64::from_ratio(1, 2, Rounding::Down).unwrap();
+            assert_eq!(quarter.sqrt(Rounding::Down), half);
+            assert_eq!(quarter.sqrt(Rounding::Up), half);
+            assert_eq!(Q64::ZERO.sqrt(Rounding::Up), Q64::ZERO);
+
+            // sqrt(2) = 1.41421356237309504880..., i.e. 26087635650665564424.7 raw
+            let down = Q64::from_int(2).sqrt(Rounding::Down);
+            let up = Q64::from_int(2).sqrt(Rounding::Up);
+            assert!(down.0 <= 26087635650665564424 && down.0 >= 26087635650665564423);
+            assert!(up.0 >= 26087635650665564425 && up.0 <= 26087635650665564426);
+        }
+
+        #[test]
+        fn q64_sqrt_boundaries() {
+            assert_eq!(Q64::from_int(1 << 62).sqrt(Rounding::Down), Q64::from_int(1 << 31));
+            let max = Q64(u128::MAX).sqrt(Rounding::Down);
+            assert_eq!(max.floor(), u32::MAX as u64);
+            assert!(Q64(u128::MAX).sqrt(Rounding::Up) > max);
         }
     }
 }
 
+/**
+ * Price representation
+ */
+pub mod price {
+    use super::math::{mul_div, Rounding};
+    use std::convert::TryFrom;
+
+    /// Prices are quoted as whole quote tokens per whole base token, scaled by
+    /// `10^PRICE_DECIMALS`, so they compare correctly across mints with
+    /// different decimals
+    pub const PRICE_DECIMALS: u32 = 9;
+    const PRICE_SCALE: u128 = 10u128.pow(PRICE_DECIMALS);
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+    pub struct Price(pub u64);
+
+    fn pow10(decimals: u8) -> Option<u128> {
+        10u128.checked_pow(decimals as u32)
+    }
+
+    impl Price {
+        /// Price implied by exchanging raw `base_amount` for raw `quote_amount`
+        pub fn from_amounts(
+            base_amount: u64,
+            base_decimals: u8,
+            quote_amount: u64,
+            quote_decimals: u8,
+            rounding: Rounding,
+        ) -> Option<Self> {
+            let numerator = (quote_amount as u128).checked_mul(pow10(base_decimals)?)?;
+            let denominator = (base_amount as u128).checked_mul(pow10(quote_decimals)?)?;
+            let price = mul_div(numerator, PRICE_SCALE, denominator, rounding)?;
+            u64::try_from(price).ok().map(Price)
+        }
+
+        /// Raw quote amount for raw `base_amount`
+        pub fn quote_for_base(
+            &self,
+            base_amount: u64,
+            base_decimals: u8,
+            quote_decimals: u8,
+            rounding: Rounding,
+        ) -> Option<u64> {
+            let numerator = (base_amount as u128).checked_mul(pow10(quote_decimals)?)?;
+            let denominator = pow10(base_decimals)?.checked_mul(PRICE_SCALE)?;
+            let quote = mul_div(numerator, self.0 as u128, denominator, rounding)?;
+            u64::try_from(quote).ok()
+        }
+
+        /// Raw base amount for raw `quote_amount`
+        pub fn base_for_quote(
+            &self,
+            quote_amount: u64,
+            base_decimals: u8,
+            quote_decimals: u8,
+            rounding: Rounding,
+        ) -> Option<u64> {
+            let numerator = (quote_amount as u128).checked_mul(pow10(base_decimals)?)?;
+            let denominator = (self.0 as u128).checked_mul(pow10(quote_decimals)?)?;
+            let base = mul_div(numerator, PRICE_SCALE, denominator, rounding)?;
+            u64::try_from(base).ok()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn price_scales_across_mismatched_decimals() {
+            // 2_000 raw at 9 decimals for 3_000 raw at 6 decimals: 1_500 per whole token
+            let price = Price::from_amounts(2_000, 9, 3_000, 6, Rounding::Down).unwrap();
+            assert_eq!(price, Price(1_500_000_000_000));
+            assert_eq!(price.quote_for_base(2_000, 9, 6, Rounding::Down), Some(3_000));
+            assert_eq!(price.base_for_quote(3_000, 9, 6, Rounding::Down), Some(2_000));
+
+            // 1 whole token at 6 decimals for 2.5 whole at 9 decimals
+            let price = Price::from_amounts(1_000_000, 6, 2_500_000_000, 9, Rounding::Down).unwrap();
+            assert_eq!(price, Price(2_500_000_000));
+            assert_eq!(price.quote_for_base(1_000_000, 6, 9, Rounding::Down), Some(2_500_000_000));
+        }
+
+        #[test]
+        fn price_rounds_in_the_requested_direction() {
+            assert_eq!(Price::from_amounts(3, 0, 1, 0, Rounding::Down), Some(Price(333_333_333)));
+            assert_eq!(Price::from_amounts(3, 0, 1, 0, Rounding::Up), Some(Price(333_333_334)));
+
+            let price = Price(333_333_334);
+            assert_eq!(price.quote_for_base(3, 0, 0, Rounding::Down), Some(1));
+            assert_eq!(price.quote_for_base(3, 0, 0, Rounding::Up), Some(2));
+            let price = Price(333_333_333);
+            assert_eq!(price.base_for_quote(1, 0, 0, Rounding::Down), Some(3));
+            assert_eq!(price.base_for_quote(1, 0, 0, Rounding::Up), Some(4));
+        }
+
+        #[test]
+        fn zero_prices_and_amounts() {
+            assert_eq!(Price::from_amounts(1_000, 6, 0, 6, Rounding::Up), Some(Price(0)));
+            assert_eq!(Price::from_amounts(0, 6, 1_000, 6, Rounding::Down), None);
+            assert_eq!(Price(0).quote_for_base(1_000, 6, 6, Rounding::Up), Some(0));
+            assert_eq!(Price(0).base_for_quote(1_000, 6, 6, Rounding::Down), None);
+        }
+
+        #[test]
+        fn price_overflow() {
+            assert_eq!(Price::from_amounts(1, 0, u64::MAX, 0, Rounding::Down), None);
+            // 10^39 doesn't fit a u128
+            assert_eq!(Price::from_amounts(1, 39, 1, 0, Rounding::Down), None);
+            assert_eq!(Price(u64::MAX).quote_for_base(u64::MAX, 0, 0, Rounding::Down), None);
+            assert_eq!(Price(1).base_for_quote(u64::MAX, 0, 0, Rounding::Down), None);
+        }
+    }
+}
+
+/**
+ * Fill receipts
+ */
+// Written to return data after a fill so calling programs can check what
+// they got without re-reading the token accounts
+#[derive(Debug, Clone, Copy, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct FillReceipt {
+    pub trade: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee_paid: u64,
+    // `None` when the price doesn't fit a `price::Price`
+    pub price: Option<u64>,
+}
+
+impl FillReceipt {
+    /// `payment` is the leg the taker actually paid with, as returned by
+    /// `Trade::payment_for_mint`
+    pub fn new(trade_key: Pubkey, trade: &Trade, payment: &PaymentOption) -> Self {
+        let price = price::Price::from_amounts(
+            trade.maker_amount,
+            trade.maker_decimals,
+            payment.amount,
+            payment.decimals,
+            math::Rounding::Down,
+        );
+        Self {
+            trade: trade_key,
+            amount_in: payment.amount,
+            amount_out: trade.maker_amount,
+            fee_paid: 0,
+            price: price.map(|price| price.0),
+        }
+    }
+
+    pub fn set_return_data(&self) {
+        // Serializing a fixed-size struct into a Vec can't fail
+        set_return_data(&borsh::to_vec(self).unwrap());
+    }
+}
+
+/**
+ * Token helpers
+ */
+fn assert_owned_by(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+    if account.owner != owner {
+        log_error!("Account {} is not owned by {}", account.key, owner);
+        return Err(ProgramError::IllegalOwner);
+    }
+    Ok(())
+}
+
+fn load_mint(mint_account: &AccountInfo, expected_mint: &Pubkey) -> Result<Mint, ProgramError> {
+    assert_owned_by(mint_account, &spl_token::id())?;
+    if mint_account.key != expected_mint {
+        return Err(DexError::MintMismatch.into());
+    }
+    Mint::unpack(&mint_account.data.borrow())
+}
+
+// Every token account an instruction takes goes through here before its data
+// is trusted
+fn check_token_account(token_account: &AccountInfo, mint: &Pubkey) -> Result<Account, ProgramError> {
+    assert_owned_by(token_account, &spl_token::id())?;
+    let account = Account::unpack(&token_account.data.borrow())?;
+    if account.mint != *mint {
+        return Err(DexError::MintMismatch.into());
+    }
+    Ok(account)
+}
+
+// Every escrow is a token account held by the program's `SIGNER_SEED` PDA
+fn escrow_authority(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(SIGNER_SEED, program_id)
+}
+
+// Loads the escrow a trade recorded at creation. The key check is what stops
+// a trade from paying out of another trade's escrow, since they all share the
+// same authority. Legacy trades never recorded one, so for them any escrow
+// the authority holds in the maker mint is accepted, as it was when they
+// were created.
+fn load_escrow(
+    trade: &Trade,
+    escrow_account: &AccountInfo,
+    escrow_authority_account: &AccountInfo,
+    program_id: &Pubkey,
+) -> Result<(Account, u8), ProgramError> {
+    if !trade.is_legacy() && *escrow_account.key != trade.escrow_pubkey {
+        log_error!("Escrow {} is not the trade's {}", escrow_account.key, trade.escrow_pubkey);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let (authority, bump) = escrow_authority(program_id);
+    if *escrow_authority_account.key != authority {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    let escrow = check_token_account(escrow_account, &trade.maker_token_pubkey)?;
+    if escrow.owner != authority {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok((escrow, bump))
+}
+
+pub struct TokenTransfer<'a, 'b> {
+    pub token_program: &'b AccountInfo<'a>,
+    pub source: &'b AccountInfo<'a>,
+    pub mint: &'b AccountInfo<'a>,
+    pub destination: &'b AccountInfo<'a>,
+    pub authority: &'b AccountInfo<'a>,
+    pub amount: u64,
+    pub decimals: u8,
+}
+
+// All token movements go through `transfer_checked` so the token program
+// rejects a mint or decimals that don't match what the trade recorded
+fn transfer_tokens(transfer: TokenTransfer, signer_seeds: &[&[&[u8]]]) -> ProgramResult {
+    let instruction = transfer_checked(
+        transfer.token_program.key,
+        transfer.source.key,
+        transfer.mint.key,
+        transfer.destination.key,
+        transfer.authority.key,
+        &[],
+        transfer.amount,
+        transfer.decimals,
+    )?;
+    invoke_signed(
+        &instruction,
+        &[
+            transfer.source.clone(),
+            transfer.mint.clone(),
+            transfer.destination.clone(),
+            transfer.authority.clone(),
+            transfer.token_program.clone(),
+        ],
+        signer_seeds,
+    )
+}
+
+// Signs with the `SIGNER_SEED` PDA, `bump` as returned by `escrow_authority`
+fn transfer_from_escrow(transfer: TokenTransfer, bump: u8) -> ProgramResult {
+    transfer_tokens(transfer, &[&[SIGNER_SEED[0], SIGNER_SEED[1], &[bump]]])
+}
+
 /**
  * Program entrypoint and instructions
  */
-#[derive(Debug, TryFromPrimitive)]
-#[repr(u8)]
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instruction_data: &[u8],
+) -> ProgramResult {
+    match DexInstruction::unpack(instruction_data)? {
+        DexInstruction::CreateTrade(params) => create_trade(program_id, accounts, params),
+        DexInstruction::CompleteTrade => complete_trade(program_id, accounts),
+        DexInstruction::UpdateTrade(params) => update_trade(program_id, accounts, params),
+        DexInstruction::GetVersion => get_version(),
+        DexInstruction::CancelTrade => cancel_trade(program_id, accounts),
+    }
+}
+
+// Programs that CPI into the DEX link this crate with `no-entrypoint` so they
+// don't pick up a second entrypoint symbol
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
+
+// Replaces the default handler so panics, including allocation failures, log
+// where they happened. `custom-panic` is this crate's feature: `entrypoint!`
+// only emits its default handler when the crate doesn't enable it.
+#[cfg(all(feature = "custom-panic", target_os = "solana", not(feature = "no-entrypoint")))]
+#[no_mangle]
+fn custom_panic(info: &core::panic::PanicInfo<'_>) {
+    msg!("DEX program panicked: {}", info);
+}
+
+// Instruction data is `[version, borsh(DexInstruction), appended fields]`:
+// - new params are only ever appended after an instruction's existing ones,
+//   and this build decodes the prefix it knows and ignores the rest, so newer
+//   clients keep working against older deployments
+// - a field appended later has to decode to a default when it's missing, so
+//   older clients keep working against newer deployments
+// - anything that isn't an append (reordering, retyping or removing a field)
+//   bumps INSTRUCTION_VERSION, and a version this build doesn't know is
+//   rejected rather than misread
+// Clients that need an appended field honoured check GetVersion first.
+pub const INSTRUCTION_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
 pub enum DexInstruction {
-    CreateTrade = 0,
-    CompleteTrade = 1,
+    CreateTrade(CreateTradeParams),
+    CompleteTrade,
+    UpdateTrade(UpdateTradeParams),
+    GetVersion,
+    CancelTrade,
+}
+
+impl DexInstruction {
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        let (&version, data) = input.split_first().ok_or(DexError::InvalidInstruction)?;
+        if version != INSTRUCTION_VERSION {
+            return Err(DexError::UnsupportedInstructionVersion.into());
+        }
+        // `deserialize` stops after the known fields, whatever follows them
+        // is appended by newer clients
+        let mut known = data;
+        Self::deserialize(&mut known).map_err(|_| DexError::InvalidInstruction.into())
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        let mut data = vec![INSTRUCTION_VERSION];
+        // Writing into a Vec can't fail
+        self.serialize(&mut data).unwrap();
+        data
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct CreateTradeParams {
+    pub taker_amount: u64,
+    pub maker_amount: u64,
+    pub taker_token_pubkey: Pubkey,
+    pub maker_token_pubkey: Pubkey,
+    pub memo: [u8; MEMO_LEN],
+    pub expiry_kind: ExpiryKind,
+    pub expiry: u64,
+    pub alternative_payments: Vec<PaymentParams>,
+}
+
+// An alternative payment as requested by the maker; decimals are read from
+// the mint account passed alongside it
+#[derive(Debug, Clone, Copy, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct PaymentParams {
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+fn validate_alternative_payments(
+    payments: &[PaymentParams],
+    taker_token_pubkey: &Pubkey,
+    maker_token_pubkey: &Pubkey,
+) -> ProgramResult {
+    if payments.len() > MAX_ALTERNATIVE_PAYMENTS {
+        return Err(ProgramError::InvalidArgument);
+    }
+    for (index, payment) in payments.iter().enumerate() {
+        if payment.amount < MINIMUM_TRADE_AMOUNT
+            || payment.mint == *taker_token_pubkey
+            || payment.mint == *maker_token_pubkey
+            || payments[..index].iter().any(|earlier| earlier.mint == payment.mint)
+        {
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
+    Ok(())
+}
+
+// Alternative payment mints follow an instruction's fixed accounts, one per
+// entry and in the same order
+fn load_alternative_payments<'a, 'b: 'a>(
+    accounts_iter: &mut impl Iterator<Item = &'a AccountInfo<'b>>,
+    payments: &[PaymentParams],
+    taker_token_pubkey: &Pubkey,
+    maker_token_pubkey: &Pubkey,
+) -> Result<Vec<PaymentOption>, ProgramError> {
+    validate_alternative_payments(payments, taker_token_pubkey, maker_token_pubkey)?;
+    payments
+        .iter()
+        .map(|payment| {
+            let mint = load_mint(next_account_info(accounts_iter)?, &payment.mint)?;
+            Ok(PaymentOption {
+                mint: payment.mint,
+                amount: payment.amount,
+                decimals: mint.decimals,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct UpdateTradeParams {
+    pub taker_amount: u64,
+    pub maker_amount: u64,
+    // `None` keeps the trade's current expiry
+    pub expiry: Option<(ExpiryKind, u64)>,
+    // Replaces the whole list, so alternative prices move with the reprice
+    pub alternative_payments: Vec<PaymentParams>,
+}
+
+// Semantic version of the deployed program, taken from the crate version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ProgramVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+// Parsed at compile time, so a crate version that doesn't fit fails the build
+// instead of reporting 0
+const fn parse_version_part(part: &str) -> u16 {
+    let bytes = part.as_bytes();
+    assert!(!bytes.is_empty(), "empty version component");
+    let mut value: u16 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "version component isn't a number");
+        value = match value.checked_mul(10) {
+            Some(value) => match value.checked_add((bytes[i] - b'0') as u16) {
+                Some(value) => value,
+                None => panic!("version component doesn't fit a u16"),
+            },
+            None => panic!("version component doesn't fit a u16"),
+        };
+        i += 1;
+    }
+    value
+}
+
+impl ProgramVersion {
+    pub const CURRENT: Self = Self {
+        major: parse_version_part(env!("CARGO_PKG_VERSION_MAJOR")),
+        minor: parse_version_part(env!("CARGO_PKG_VERSION_MINOR")),
+        patch: parse_version_part(env!("CARGO_PKG_VERSION_PATCH")),
+    };
+
+    pub fn current() -> Self {
+        Self::CURRENT
+    }
+
+    /// Clients built against a version semver treats as compatible can talk
+    /// to this program: the same major, or below 1.0 the same minor, and below
+    /// 0.1 the same patch
+    pub fn is_compatible_with(&self, other: &ProgramVersion) -> bool {
+        match (self.major, self.minor) {
+            (0, 0) => self == other,
+            (0, minor) => other.major == 0 && other.minor == minor,
+            (major, _) => other.major == major,
+        }
+    }
 }
 
-struct CreateTradeParams {
-    taker_amount: u64,
-    maker_amount: u64,
-    taker_token_pubkey: Pubkey,
-    maker_token_pubkey: Pubkey,
+fn get_version() -> ProgramResult {
+    // Serializing a fixed-size struct into a Vec can't fail
+    set_return_data(&borsh::to_vec(&ProgramVersion::current()).unwrap());
+    Ok(())
+}
+
+fn update_trade(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    params: UpdateTradeParams
+) -> ProgramResult {
+    log_info!("Instruction: UpdateTrade");
+    let accounts_iter = &mut accounts.iter();
+    let trade_account = next_account_info(accounts_iter)?;
+    let maker_account = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let escrow_authority_account = next_account_info(accounts_iter)?;
+    let maker_token_account = next_account_info(accounts_iter)?;
+    let maker_mint_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+
+    if !maker_account.is_signer {
+        return Err(DexError::MissingRequiredSignatureMaker.into());
+    }
+    assert_owned_by(trade_account, program_id)?;
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut trade = Trade::load(&trade_account.data.borrow())?;
+    if !trade.is_initialized() {
+        return Err(DexError::TradeNotFound.into());
+    }
+    // Repricing keeps the status, which only open trades allow
+    let status = trade.status();
+    trade.set_status(status)?;
+    // The legacy layout has nowhere to keep the new terms; those trades can
+    // only be filled or cancelled
+    if trade.is_legacy() {
+        return Err(DexError::UnsupportedTradeLayout.into());
+    }
+    let clock = Clock::get()?;
+    if trade.is_expired(&clock) {
+        return Err(DexError::TradeExpired.into());
+    }
+    if let Some((expiry_kind, expiry)) = params.expiry {
+        check_expiry(expiry_kind, expiry, &clock)?;
+    }
+    if trade.maker_pubkey != *maker_account.key {
+        log_error!("Signer {} is not the maker of trade {}", maker_account.key, trade_account.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if params.taker_amount < MINIMUM_TRADE_AMOUNT || params.maker_amount < MINIMUM_TRADE_AMOUNT {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (escrow, escrow_bump) =
+        load_escrow(&trade, escrow_account, escrow_authority_account, program_id)?;
+    if check_token_account(maker_token_account, &trade.maker_token_pubkey)?.owner != *maker_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let maker_mint = load_mint(maker_mint_account, &trade.maker_token_pubkey)?;
+    let alternative_payments = load_alternative_payments(
+        accounts_iter,
+        &params.alternative_payments,
+        &trade.taker_token_pubkey,
+        &trade.maker_token_pubkey,
+    )?;
+
+    // What the trade escrowed has to cover the new size. A larger trade
+    // takes a CancelTrade and a new CreateTrade; shrinking returns the
+    // difference to the maker. Refunds follow the trade's own `maker_amount` rather than the
+    // escrow balance, so tokens anyone else sent to the escrow stay there.
+    if params.maker_amount > trade.maker_amount || escrow.amount < params.maker_amount {
+        return Err(DexError::InsufficientFunds.into());
+    }
+    let refund = trade.maker_amount - params.maker_amount;
+    if refund > 0 {
+        transfer_from_escrow(
+            TokenTransfer {
+                token_program,
+                source: escrow_account,
+                mint: maker_mint_account,
+                destination: maker_token_account,
+                authority: escrow_authority_account,
+                amount: refund,
+                decimals: maker_mint.decimals,
+            },
+            escrow_bump,
+        )?;
+    }
+
+    trade.taker_amount = params.taker_amount;
+    trade.maker_amount = params.maker_amount;
+    if let Some((expiry_kind, expiry)) = params.expiry {
+        trade.set_expiry(expiry_kind, expiry);
+    }
+    trade.set_alternative_payments(&alternative_payments)?;
+    trade.store(&mut trade_account.data.borrow_mut())?;
+
+    log_json!(
+        "trade_updated",
+        trade = trade_account.key,
+        taker_amount = trade.taker_amount,
+        maker_amount = trade.maker_amount,
+        escrow_before = escrow.amount,
+        escrow_after = escrow.amount - refund,
+    );
+    Ok(())
 }
 
 fn create_trade(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
     params: CreateTradeParams
 ) -> ProgramResult {
+    log_info!("Instruction: CreateTrade");
     let accounts_iter = &mut accounts.iter();
     let trade_account = next_account_info(accounts_iter)?;
-    let taker_account = next_account_info(accounts_iter)?;
+    // Not recorded: any taker can fill a trade
+    let _taker_account = next_account_info(accounts_iter)?;
     let maker_account = next_account_info(accounts_iter)?;
+    let maker_mint_account = next_account_info(accounts_iter)?;
+    let taker_mint_account = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let maker_token_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+
+    if !maker_account.is_signer {
+        log_error!("Maker {} did not sign", maker_account.key);
+        return Err(DexError::MissingRequiredSignatureMaker.into());
+    }
+
+    // Decimals are recorded from the mints themselves so prices can't be
+    // skewed by what the caller claims
+    let maker_mint = load_mint(maker_mint_account, &params.maker_token_pubkey)?;
+    let taker_mint = load_mint(taker_mint_account, &params.taker_token_pubkey)?;
+
+    // The escrow has to be held by the program; the trade records its key so
+    // later instructions can't be pointed at another trade's escrow
+    let (escrow_authority, _) = escrow_authority(program_id);
+    if check_token_account(escrow_account, &params.maker_token_pubkey)?.owner != escrow_authority {
+        log_error!("Escrow {} is not held by the program", escrow_account.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    check_token_account(maker_token_account, &params.maker_token_pubkey)?;
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    if params.taker_amount < MINIMUM_TRADE_AMOUNT || params.maker_amount < MINIMUM_TRADE_AMOUNT {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let alternative_payments = load_alternative_payments(
+        accounts_iter,
+        &params.alternative_payments,
+        &params.taker_token_pubkey,
+        &params.maker_token_pubkey,
+    )?;
+
+    // Reject trades that would be born expired
+    let clock = Clock::get()?;
+    check_expiry(params.expiry_kind, params.expiry, &clock)?;
+
+    assert_owned_by(trade_account, program_id)?;
 
     // Verify the rent exemption
-    let rent = &Rent::from_account_info(next_account_info(accounts_iter)?)?;
+    let rent = Rent::get()?;
     if !rent.is_exempt(trade_account.lamports(), trade_account.data_len()) {
+        log_error!("Trade account {} is not rent exempt", trade_account.key);
         return Err(ProgramError::AccountNotRentExempt);
     }
 
     // Check the trade doesn't already exist
-    if trade_account.lamports() > 0 {
+    if Trade::load(&trade_account.data.borrow())?.is_initialized() {
+        log_error!("Trade account {} already exists", trade_account.key);
         return Err(DexError::TradeAlreadyExist.into());
+    }
+
+    // The maker signs for moving `maker_amount` into the escrow
+    transfer_tokens(
+        TokenTransfer {
+            token_program,
+            source: maker_token_account,
+            mint: maker_mint_account,
+            destination: escrow_account,
+            authority: maker_account,
+            amount: params.maker_amount,
+            decimals: maker_mint.decimals,
+        },
+        &[],
+    )?;
+
+    let mut trade = Trade::new(TradeTerms {
+        maker_pubkey: *maker_account.key,
+        taker_amount: params.taker_amount,
+        maker_amount: params.maker_amount,
+        taker_token_pubkey: params.taker_token_pubkey,
+        maker_token_pubkey: params.maker_token_pubkey,
+        taker_decimals: taker_mint.decimals,
+        maker_decimals: maker_mint.decimals,
+        trade_timestamp: clock.unix_timestamp,
+        memo: params.memo,
+        expiry_kind: params.expiry_kind,
+        expiry: params.expiry,
+        escrow_pubkey: *escrow_account.key,
+    });
+    trade.set_status(TradeStatus::Open)?;
+    trade.set_alternative_payments(&alternative_payments)?;
+    Trade::pack(trade, &mut trade_account.data.borrow_mut())?;
+
+    log_json!(
+        "trade_created",
+        trade = trade_account.key,
+        maker = maker_account.key,
+        taker_amount = trade.taker_amount,
+        maker_amount = trade.maker_amount,
+        alternative_payments = alternative_payments.len(),
+    );
+    Ok(())
+}
+
+fn complete_trade(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    log_info!("Instruction: CompleteTrade");
+    let accounts_iter = &mut accounts.iter();
+    let trade_account = next_account_info(accounts_iter)?;
+    let taker_account = next_account_info(accounts_iter)?;
+    let taker_payment_account = next_account_info(accounts_iter)?;
+    let maker_payment_account = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let escrow_authority_account = next_account_info(accounts_iter)?;
+    let taker_receive_account = next_account_info(accounts_iter)?;
+    let payment_mint_account = next_account_info(accounts_iter)?;
+    let maker_mint_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+
+    if !taker_account.is_signer {
+        log_error!("Taker {} did not sign", taker_account.key);
+        return Err(DexError::MissingRequiredSignatureTaker.into());
+    }
+    assert_owned_by(trade_account, program_id)?;
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut trade = Trade::load(&trade_account.data.borrow())?;
+    if !trade.is_initialized() {
+        return Err(DexError::TradeNotFound.into());
+    }
+    if trade.is_expired(&Clock::get()?) {
+        return Err(DexError::TradeExpired.into());
+    }
+    trade.set_status(TradeStatus::Filled)?;
+
+    // The taker pays with whichever leg the payment mint picks out
+    let mut payment = trade.payment_for_mint(payment_mint_account.key).ok_or(DexError::MintMismatch)?;
+    let payment_mint = load_mint(payment_mint_account, &payment.mint)?;
+    let maker_mint = load_mint(maker_mint_account, &trade.maker_token_pubkey)?;
+    // Legacy trades didn't record decimals; the receipt prices them off the mints
+    if trade.is_legacy() {
+        payment.decimals = payment_mint.decimals;
+        trade.maker_decimals = maker_mint.decimals;
+    }
+    if check_token_account(maker_payment_account, &payment.mint)?.owner != trade.maker_pubkey {
+        log_error!("Payment account {} is not the maker's", maker_payment_account.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    check_token_account(taker_payment_account, &payment.mint)?;
+    check_token_account(taker_receive_account, &trade.maker_token_pubkey)?;
+    let (escrow, escrow_bump) =
+        load_escrow(&trade, escrow_account, escrow_authority_account, program_id)?;
+    if escrow.amount < trade.maker_amount {
+        return Err(DexError::InsufficientFunds.into());
+    }
+
+    transfer_tokens(
+        TokenTransfer {
+            token_program,
+            source: taker_payment_account,
+            mint: payment_mint_account,
+            destination: maker_payment_account,
+            authority: taker_account,
+            amount: payment.amount,
+            decimals: payment_mint.decimals,
+        },
+        &[],
+    )?;
+    transfer_from_escrow(
+        TokenTransfer {
+            token_program,
+            source: escrow_account,
+            mint: maker_mint_account,
+            destination: taker_receive_account,
+            authority: escrow_authority_account,
+            amount: trade.maker_amount,
+            decimals: maker_mint.decimals,
+        },
+        escrow_bump,
+    )?;
+
+    trade.store(&mut trade_account.data.borrow_mut())?;
+
+    let receipt = FillReceipt::new(*trade_account.key, &trade, &payment);
+    receipt.set_return_data();
+    log_json!(
+        "trade_completed",
+        trade = trade_account.key,
+        taker = taker_account.key,
+        payment_mint = payment.mint,
+        amount_in = receipt.amount_in,
+        amount_out = receipt.amount_out,
+    );
+    Ok(())
+}
+
+// Closes an open trade and returns its escrowed `maker_amount` to the maker.
+// A trade past its expiry is recorded as Expired rather than Cancelled.
+fn cancel_trade(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    log_info!("Instruction: CancelTrade");
+    let accounts_iter = &mut accounts.iter();
+    let trade_account = next_account_info(accounts_iter)?;
+    let maker_account = next_account_info(accounts_iter)?;
+    let escrow_account = next_account_info(accounts_iter)?;
+    let escrow_authority_account = next_account_info(accounts_iter)?;
+    let maker_token_account = next_account_info(accounts_iter)?;
+    let maker_mint_account = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+
+    if !maker_account.is_signer {
+        return Err(DexError::MissingRequiredSignatureMaker.into());
+    }
+    assert_owned_by(trade_account, program_id)?;
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let mut trade = Trade::load(&trade_account.data.borrow())?;
+    if !trade.is_initialized() {
+        return Err(DexError::TradeNotFound.into());
+    }
+    if trade.maker_pubkey != *maker_account.key {
+        log_error!("Signer {} is not the maker of trade {}", maker_account.key, trade_account.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let status = if trade.is_expired(&Clock::get()?) {
+        TradeStatus::Expired
+    } else {
+        TradeStatus::Cancelled
+    };
+    trade.set_status(status)?;
+
+    let (_, escrow_bump) = load_escrow(&trade, escrow_account, escrow_authority_account, program_id)?;
+    if check_token_account(maker_token_account, &trade.maker_token_pubkey)?.owner != *maker_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let maker_mint = load_mint(maker_mint_account, &trade.maker_token_pubkey)?;
+    transfer_from_escrow(
+        TokenTransfer {
+            token_program,
+            source: escrow_account,
+            mint: maker_mint_account,
+            destination: maker_token_account,
+            authority: escrow_authority_account,
+            amount: trade.maker_amount,
+            decimals: maker_mint.decimals,
+        },
+        escrow_bump,
+    )?;
+    trade.store(&mut trade_account.data.borrow_mut())?;
+
+    log_json!(
+        "trade_cancelled",
+        trade = trade_account.key,
+        status = format!("{:?}", trade.status()),
+        refund = trade.maker_amount,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::{entrypoint::SUCCESS, instruction::Instruction, program_stubs};
+    use spl_token::state::AccountState;
+
+    fn open_trade() -> Trade {
+        let mut trade = Trade::new(TradeTerms {
+            maker_pubkey: Pubkey::new_unique(),
+            taker_amount: 1_000,
+            maker_amount: 2_000,
+            taker_token_pubkey: Pubkey::new_unique(),
+            maker_token_pubkey: Pubkey::new_unique(),
+            taker_decimals: 6,
+            maker_decimals: 9,
+            trade_timestamp: 1_700_000_000,
+            memo: [7; MEMO_LEN],
+            expiry_kind: ExpiryKind::Slot,
+            expiry: 500,
+            escrow_pubkey: Pubkey::new_unique(),
+        });
+        trade.set_status(TradeStatus::Open).unwrap();
+        trade
+    }
+
+    fn create_trade_params() -> CreateTradeParams {
+        CreateTradeParams {
+            taker_amount: 1_000,
+            maker_amount: 2_000,
+            taker_token_pubkey: Pubkey::new_unique(),
+            maker_token_pubkey: Pubkey::new_unique(),
+            memo: [7; MEMO_LEN],
+            expiry_kind: ExpiryKind::UnixTimestamp,
+            expiry: 1_800_000_000,
+            alternative_payments: vec![PaymentParams {
+                mint: Pubkey::new_unique(),
+                amount: 1_500,
+            }],
+        }
+    }
+
+    // Owns what an AccountInfo borrows, so handlers can be called directly
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+    }
+
+    impl TestAccount {
+        fn new(owner: Pubkey, data: Vec<u8>) -> Self {
+            Self::with_key(Pubkey::new_unique(), owner, data)
+        }
+
+        fn with_key(key: Pubkey, owner: Pubkey, data: Vec<u8>) -> Self {
+            Self {
+                key,
+                owner,
+                lamports: 1_000_000_000,
+                data,
+            }
+        }
+
+        fn info(&mut self, is_signer: bool) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                is_signer,
+                true,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                0,
+            )
+        }
+    }
+
+    fn test_clock() -> Clock {
+        Clock {
+            slot: 100,
+            unix_timestamp: 1_700_000_000,
+            ..Clock::default()
+        }
+    }
+
+    thread_local! {
+        static RETURN_DATA: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    // Serves the sysvars off-chain and runs token program CPIs in-process,
+    // granting the PDA signatures the seeds would on chain
+    struct TestSyscalls;
+
+    impl program_stubs::SyscallStubs for TestSyscalls {
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Clock) = test_clock() };
+            SUCCESS
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            SUCCESS
+        }
+
+        fn sol_set_return_data(&self, data: &[u8]) {
+            RETURN_DATA.with(|return_data| *return_data.borrow_mut() = data.to_vec());
+        }
+
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            let signers: Vec<Pubkey> = signers_seeds
+                .iter()
+                .map(|seeds| Pubkey::create_program_address(seeds, &id()).unwrap())
+                .collect();
+            let accounts: Vec<AccountInfo> = instruction
+                .accounts
+                .iter()
+                .map(|meta| {
+                    let mut info = account_infos.iter().find(|info| *info.key == meta.pubkey).unwrap().clone();
+                    info.is_signer |= signers.contains(info.key);
+                    info
+                })
+                .collect();
+            spl_token::processor::Processor::process(&instruction.program_id, &accounts, &instruction.data)
+        }
+    }
+
+    // Handlers under test take `id()` as their program ID so PDA signatures
+    // check out
+    fn install_test_syscalls() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            program_stubs::set_syscall_stubs(Box::new(TestSyscalls));
+        });
+    }
+
+    fn system_accounts(count: usize) -> Vec<TestAccount> {
+        (0..count).map(|_| TestAccount::new(Pubkey::default(), vec![])).collect()
+    }
+
+    // Only the account at `signer` can sign, and only when `signed` is set
+    fn account_infos(
+        accounts: &mut [TestAccount],
+        signer: usize,
+        signed: bool,
+    ) -> Vec<AccountInfo<'_>> {
+        accounts
+            .iter_mut()
+            .enumerate()
+            .map(|(index, account)| account.info(signed && index == signer))
+            .collect()
+    }
+
+    fn all_instructions() -> Vec<DexInstruction> {
+        vec![
+            DexInstruction::CreateTrade(create_trade_params()),
+            DexInstruction::CompleteTrade,
+            DexInstruction::UpdateTrade(UpdateTradeParams {
+                taker_amount: 3_000,
+                maker_amount: 4_000,
+                expiry: Some((ExpiryKind::Slot, 1_000)),
+                alternative_payments: vec![],
+            }),
+            DexInstruction::GetVersion,
+            DexInstruction::CancelTrade,
+        ]
+    }
+
+    #[test]
+    fn instructions_round_trip() {
+        for instruction in all_instructions() {
+            let data = instruction.pack();
+            assert_eq!(data[0], INSTRUCTION_VERSION);
+            assert_eq!(DexInstruction::unpack(&data).unwrap(), instruction);
+        }
+    }
+
+    #[test]
+    fn instruction_versions_outside_this_build_are_rejected() {
+        for version in [0, INSTRUCTION_VERSION + 1, u8::MAX] {
+            let mut data = DexInstruction::GetVersion.pack();
+            data[0] = version;
+            assert_eq!(
+                DexInstruction::unpack(&data),
+                Err(DexError::UnsupportedInstructionVersion.into())
+            );
+        }
+    }
+
+    #[test]
+    fn unreachable_unix_expiries_are_rejected() {
+        let clock = Clock {
+            slot: 100,
+            unix_timestamp: 1_700_000_000,
+            ..Clock::default()
+        };
+        let wrapping = i64::MAX as u64 + 1;
+        assert!(!is_past_expiry(ExpiryKind::UnixTimestamp, wrapping, &clock));
+        assert!(!is_past_expiry(ExpiryKind::UnixTimestamp, u64::MAX, &clock));
+        for expiry in [wrapping, u64::MAX] {
+            assert_eq!(
+                check_expiry(ExpiryKind::UnixTimestamp, expiry, &clock),
+                Err(DexError::InvalidExpiry.into())
+            );
+        }
+        assert_eq!(
+            check_expiry(ExpiryKind::UnixTimestamp, 1_700_000_000, &clock),
+            Err(DexError::TradeExpired.into())
+        );
+        assert_eq!(check_expiry(ExpiryKind::UnixTimestamp, i64::MAX as u64, &clock), Ok(()));
+        // Slots are u64 already, so any value is reachable
+        assert_eq!(check_expiry(ExpiryKind::Slot, u64::MAX, &clock), Ok(()));
+        assert_eq!(check_expiry(ExpiryKind::Never, u64::MAX, &clock), Ok(()));
+    }
+
+    #[test]
+    fn malformed_instructions_are_rejected() {
+        let invalid = Err(DexError::InvalidInstruction.into());
+        assert_eq!(DexInstruction::unpack(&[]), invalid);
+        assert_eq!(DexInstruction::unpack(&[INSTRUCTION_VERSION]), invalid);
+        // Unknown tag
+        assert_eq!(DexInstruction::unpack(&[INSTRUCTION_VERSION, 200]), invalid);
+        for instruction in all_instructions() {
+            let data = instruction.pack();
+            // Truncated payload
+            if data.len() > 2 {
+                assert_eq!(DexInstruction::unpack(&data[..data.len() - 1]), invalid);
+            }
+        }
+    }
+
+    #[test]
+    fn appended_fields_are_ignored() {
+        for instruction in all_instructions() {
+            // e.g. a newer client's optional u64 and flag
+            let mut data = instruction.pack();
+            data.extend_from_slice(&42u64.to_le_bytes());
+            data.push(1);
+            assert_eq!(DexInstruction::unpack(&data).unwrap(), instruction);
+        }
+    }
+
+    #[test]
+    fn alternative_payments_must_be_distinct_new_mints() {
+        let taker_mint = Pubkey::new_unique();
+        let maker_mint = Pubkey::new_unique();
+        let other_mint = Pubkey::new_unique();
+        let payment = |mint, amount| PaymentParams { mint, amount };
+        let validate = |payments: &[PaymentParams]| {
+            validate_alternative_payments(payments, &taker_mint, &maker_mint)
+        };
+
+        assert_eq!(validate(&[payment(other_mint, 500)]), Ok(()));
+        let invalid = Err(ProgramError::InvalidArgument);
+        assert_eq!(validate(&[payment(taker_mint, 500)]), invalid);
+        assert_eq!(validate(&[payment(maker_mint, 500)]), invalid);
+        assert_eq!(validate(&[payment(other_mint, 500), payment(other_mint, 600)]), invalid);
+        assert_eq!(validate(&[payment(other_mint, MINIMUM_TRADE_AMOUNT - 1)]), invalid);
+        let too_many: Vec<_> = (0..=MAX_ALTERNATIVE_PAYMENTS)
+            .map(|_| payment(Pubkey::new_unique(), 500))
+            .collect();
+        assert_eq!(validate(&too_many), invalid);
+    }
+
+    #[test]
+    fn fill_receipt_reports_the_payment_leg_used() {
+        let mut trade = open_trade();
+        let alternative = PaymentOption {
+            mint: Pubkey::new_unique(),
+            amount: 3_000,
+            decimals: 6,
+        };
+        trade.set_alternative_payments(&[alternative]).unwrap();
+
+        let payment = trade.payment_for_mint(&alternative.mint).unwrap();
+        let receipt = FillReceipt::new(Pubkey::new_unique(), &trade, &payment);
+        assert_eq!(receipt.amount_in, 3_000);
+        assert_eq!(receipt.amount_out, trade.maker_amount);
+        // 2_000 raw at 9 decimals for 3_000 raw at 6 decimals
+        assert_eq!(receipt.price, Some(1_500_000_000_000));
+
+        let primary = trade.payment_for_mint(&trade.taker_token_pubkey).unwrap();
+        let receipt = FillReceipt::new(Pubkey::new_unique(), &trade, &primary);
+        assert_eq!(receipt.amount_in, trade.taker_amount);
+        assert_eq!(receipt.price, Some(trade.price().unwrap().0));
+
+        // A price past u64 is reported as missing rather than as zero
+        trade.maker_amount = 1;
+        let overflowing = PaymentOption {
+            amount: u64::MAX,
+            ..primary
+        };
+        assert_eq!(FillReceipt::new(Pubkey::new_unique(), &trade, &overflowing).price, None);
+    }
+
+    #[test]
+    fn handlers_require_the_maker_signature() {
+        let program_id = Pubkey::new_unique();
+        let update_params = UpdateTradeParams {
+            taker_amount: 3_000,
+            maker_amount: 4_000,
+            expiry: None,
+            alternative_payments: vec![],
+        };
+
+        // The maker is create_trade's third account
+        let mut accounts = system_accounts(8);
+        assert_eq!(
+            create_trade(&program_id, &account_infos(&mut accounts, 2, false), create_trade_params()),
+            Err(DexError::MissingRequiredSignatureMaker.into())
+        );
+        // Once signed it gets as far as the mint owner check
+        assert_eq!(
+            create_trade(&program_id, &account_infos(&mut accounts, 2, true), create_trade_params()),
+            Err(ProgramError::IllegalOwner)
+        );
+
+        // and update_trade's second
+        let mut accounts = system_accounts(7);
+        assert_eq!(
+            update_trade(&program_id, &account_infos(&mut accounts, 1, false), update_params.clone()),
+            Err(DexError::MissingRequiredSignatureMaker.into())
+        );
+        assert_eq!(
+            update_trade(&program_id, &account_infos(&mut accounts, 1, true), update_params),
+            Err(ProgramError::IllegalOwner)
+        );
+    }
+
+    fn token_account(mint: Pubkey, owner: Pubkey, amount: u64) -> Vec<u8> {
+        let mut data = vec![0; Account::LEN];
+        Account::pack(
+            Account {
+                mint,
+                owner,
+                amount,
+                state: AccountState::Initialized,
+                ..Account::default()
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn token_accounts_must_belong_to_the_token_program() {
+        let mint = Pubkey::new_unique();
+        let data = token_account(mint, Pubkey::new_unique(), 2_000);
+
+        let mut account = TestAccount::new(spl_token::id(), data.clone());
+        assert!(check_token_account(&account.info(false), &mint).is_ok());
+        assert_eq!(
+            check_token_account(&account.info(false), &Pubkey::new_unique()),
+            Err(DexError::MintMismatch.into())
+        );
+
+        // Same bytes, but any program could have written them
+        for owner in [Pubkey::default(), Pubkey::new_unique()] {
+            let mut account = TestAccount::new(owner, data.clone());
+            assert_eq!(
+                check_token_account(&account.info(false), &mint),
+                Err(ProgramError::IllegalOwner)
+            );
+        }
+    }
+
+    fn mint_account(decimals: u8) -> Vec<u8> {
+        let mut data = vec![0; Mint::LEN];
+        Mint::pack(
+            Mint {
+                decimals,
+                is_initialized: true,
+                ..Mint::default()
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn mints_must_belong_to_the_token_program() {
+        let data = mint_account(6);
+        let mut mint = TestAccount::new(spl_token::id(), data.clone());
+        let key = mint.key;
+        assert_eq!(load_mint(&mint.info(false), &key).unwrap().decimals, 6);
+        assert_eq!(
+            load_mint(&mint.info(false), &Pubkey::new_unique()),
+            Err(DexError::MintMismatch.into())
+        );
+
+        let mut mint = TestAccount::new(Pubkey::new_unique(), data);
+        let key = mint.key;
+        assert_eq!(load_mint(&mint.info(false), &key), Err(ProgramError::IllegalOwner));
+    }
+
+    #[test]
+    fn handlers_reject_accounts_with_the_wrong_owner() {
+        let program_id = Pubkey::new_unique();
+        let mut trade_data = vec![0; Trade::LEN];
+        Trade::pack(open_trade(), &mut trade_data).unwrap();
+
+        // A valid trade owned by another program
+        let mut accounts = system_accounts(7);
+        accounts[0] = TestAccount::new(Pubkey::new_unique(), trade_data);
+        let params = UpdateTradeParams {
+            taker_amount: 3_000,
+            maker_amount: 4_000,
+            expiry: None,
+            alternative_payments: vec![],
+        };
+        assert_eq!(
+            update_trade(&program_id, &account_infos(&mut accounts, 1, true), params),
+            Err(ProgramError::IllegalOwner)
+        );
+
+        // Valid mints, then an escrow that isn't a token program account
+        let params = create_trade_params();
+        let mut accounts = system_accounts(8);
+        accounts[3] = TestAccount::new(spl_token::id(), mint_account(9));
+        accounts[3].key = params.maker_token_pubkey;
+        accounts[4] = TestAccount::new(spl_token::id(), mint_account(6));
+        accounts[4].key = params.taker_token_pubkey;
+        let (escrow_authority, _) = escrow_authority(&program_id);
+        accounts[5] = TestAccount::new(
+            Pubkey::new_unique(),
+            token_account(params.maker_token_pubkey, escrow_authority, 0),
+        );
+        assert_eq!(
+            create_trade(&program_id, &account_infos(&mut accounts, 2, true), params.clone()),
+            Err(ProgramError::IllegalOwner)
+        );
+
+        // A real token account, but not held by the program
+        accounts[5] = TestAccount::new(
+            spl_token::id(),
+            token_account(params.maker_token_pubkey, accounts[2].key, 0),
+        );
+        assert_eq!(
+            create_trade(&program_id, &account_infos(&mut accounts, 2, true), params),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn status_transitions() {
+        use TradeStatus::*;
+        let statuses = [Uninitialized, Open, PartiallyFilled, Filled, Cancelled, Expired];
+        let allowed = [
+            (Uninitialized, Open),
+            (Open, Open),
+            (Open, PartiallyFilled),
+            (Open, Filled),
+            (Open, Cancelled),
+            (Open, Expired),
+            (PartiallyFilled, PartiallyFilled),
+            (PartiallyFilled, Filled),
+            (PartiallyFilled, Cancelled),
+            (PartiallyFilled, Expired),
+        ];
+        for from in statuses {
+            for to in statuses {
+                let mut trade = open_trade();
+                trade.status = from;
+                let expected = if allowed.contains(&(from, to)) {
+                    Ok(())
+                } else {
+                    Err(DexError::InvalidStatusTransition.into())
+                };
+                assert_eq!(trade.set_status(to), expected, "{:?} -> {:?}", from, to);
+                assert_eq!(trade.status(), if expected.is_ok() { to } else { from });
+            }
+        }
+    }
+
+    fn token_amount(account: &TestAccount) -> u64 {
+        Account::unpack(&account.data).unwrap().amount
+    }
+
+    // Accounts for a CreateTrade that succeeds, in instruction order
+    fn create_trade_accounts(params: &CreateTradeParams) -> Vec<TestAccount> {
+        let (authority, _) = escrow_authority(&id());
+        let maker = TestAccount::new(Pubkey::default(), vec![]);
+        let maker_tokens = token_account(params.maker_token_pubkey, maker.key, params.maker_amount);
+        let mut accounts = vec![
+            TestAccount::new(id(), vec![0; Trade::LEN]),
+            TestAccount::new(Pubkey::default(), vec![]),
+            maker,
+            TestAccount::with_key(params.maker_token_pubkey, spl_token::id(), mint_account(9)),
+            TestAccount::with_key(params.taker_token_pubkey, spl_token::id(), mint_account(6)),
+            TestAccount::new(spl_token::id(), token_account(params.maker_token_pubkey, authority, 0)),
+            TestAccount::new(spl_token::id(), maker_tokens),
+            TestAccount::with_key(spl_token::id(), Pubkey::default(), vec![]),
+        ];
+        for payment in &params.alternative_payments {
+            accounts.push(TestAccount::with_key(payment.mint, spl_token::id(), mint_account(8)));
+        }
+        accounts
+    }
+
+    #[test]
+    fn create_trade_escrows_and_records_the_trade() {
+        install_test_syscalls();
+        let params = create_trade_params();
+        let mut accounts = create_trade_accounts(&params);
+        create_trade(&id(), &account_infos(&mut accounts, 2, true), params.clone()).unwrap();
+
+        let trade = Trade::unpack(&accounts[0].data).unwrap();
+        assert_eq!(trade.status(), TradeStatus::Open);
+        assert_eq!(trade.maker_pubkey, accounts[2].key);
+        assert_eq!((trade.maker_decimals, trade.taker_decimals), (9, 6));
+        assert_eq!(trade.trade_timestamp, test_clock().unix_timestamp);
+        assert_eq!(trade.escrow_pubkey, accounts[5].key);
+        assert_eq!((trade.expiry_kind(), trade.expiry), (params.expiry_kind, params.expiry));
+        assert_eq!(
+            trade.alternative_payments(),
+            &[PaymentOption {
+                mint: params.alternative_payments[0].mint,
+                amount: params.alternative_payments[0].amount,
+                decimals: 8,
+            }]
+        );
+        assert_eq!(token_amount(&accounts[5]), params.maker_amount);
+        assert_eq!(token_amount(&accounts[6]), 0);
+
+        // The account now holds a trade
+        assert_eq!(
+            create_trade(&id(), &account_infos(&mut accounts, 2, true), params),
+            Err(DexError::TradeAlreadyExist.into())
+        );
+    }
+
+    #[test]
+    fn create_trade_needs_the_maker_funds() {
+        install_test_syscalls();
+        let params = create_trade_params();
+        let mut accounts = create_trade_accounts(&params);
+        accounts[6].data = token_account(params.maker_token_pubkey, accounts[2].key, params.maker_amount - 1);
+        assert_eq!(
+            create_trade(&id(), &account_infos(&mut accounts, 2, true), params),
+            Err(spl_token::error::TokenError::InsufficientFunds.into())
+        );
+        assert!(!Trade::unpack_unchecked(&accounts[0].data).unwrap().is_initialized());
+    }
+
+    // Accounts for a CompleteTrade paying in `payment_mint`, in instruction
+    // order, with `trade` stored in the first and its escrow funded
+    fn complete_trade_accounts(trade: &Trade, payment_mint: Pubkey, decimals: u8) -> Vec<TestAccount> {
+        let (authority, _) = escrow_authority(&id());
+        let taker = TestAccount::new(Pubkey::default(), vec![]);
+        let taker_payment = token_account(payment_mint, taker.key, 5_000);
+        let taker_receive = token_account(trade.maker_token_pubkey, taker.key, 0);
+        let mut trade_data = vec![0; Trade::LEN];
+        Trade::pack(*trade, &mut trade_data).unwrap();
+        vec![
+            TestAccount::new(id(), trade_data),
+            taker,
+            TestAccount::new(spl_token::id(), taker_payment),
+            TestAccount::new(spl_token::id(), token_account(payment_mint, trade.maker_pubkey, 0)),
+            TestAccount::with_key(
+                trade.escrow_pubkey,
+                spl_token::id(),
+                token_account(trade.maker_token_pubkey, authority, trade.maker_amount),
+            ),
+            TestAccount::with_key(authority, Pubkey::default(), vec![]),
+            TestAccount::new(spl_token::id(), taker_receive),
+            TestAccount::with_key(payment_mint, spl_token::id(), mint_account(decimals)),
+            TestAccount::with_key(trade.maker_token_pubkey, spl_token::id(), mint_account(trade.maker_decimals)),
+            TestAccount::with_key(spl_token::id(), Pubkey::default(), vec![]),
+        ]
+    }
+
+    #[test]
+    fn complete_trade_swaps_and_returns_a_receipt() {
+        install_test_syscalls();
+        let mut trade = open_trade();
+        let alternative = PaymentOption {
+            mint: Pubkey::new_unique(),
+            amount: 3_000,
+            decimals: 8,
+        };
+        trade.set_alternative_payments(&[alternative]).unwrap();
+        let mut accounts = complete_trade_accounts(&trade, alternative.mint, alternative.decimals);
+        complete_trade(&id(), &account_infos(&mut accounts, 1, true)).unwrap();
+
+        assert_eq!(Trade::unpack(&accounts[0].data).unwrap().status(), TradeStatus::Filled);
+        assert_eq!(token_amount(&accounts[2]), 2_000);
+        assert_eq!(token_amount(&accounts[3]), alternative.amount);
+        assert_eq!(token_amount(&accounts[4]), 0);
+        assert_eq!(token_amount(&accounts[6]), trade.maker_amount);
+
+        let receipt = RETURN_DATA.with(|data| FillReceipt::try_from_slice(&data.borrow()).unwrap());
+        assert_eq!(receipt, FillReceipt::new(accounts[0].key, &trade, &alternative));
+
+        // A filled trade can't be filled again
+        assert_eq!(
+            complete_trade(&id(), &account_infos(&mut accounts, 1, true)),
+            Err(DexError::InvalidStatusTransition.into())
+        );
+    }
+
+    #[test]
+    fn complete_trade_only_takes_the_trade_payment_mints() {
+        install_test_syscalls();
+        let trade = open_trade();
+        let mut accounts = complete_trade_accounts(&trade, Pubkey::new_unique(), 6);
+        assert_eq!(
+            complete_trade(&id(), &account_infos(&mut accounts, 1, true)),
+            Err(DexError::MintMismatch.into())
+        );
+
+        let mut accounts = complete_trade_accounts(&trade, trade.taker_token_pubkey, trade.taker_decimals);
+        assert_eq!(
+            complete_trade(&id(), &account_infos(&mut accounts, 1, false)),
+            Err(DexError::MissingRequiredSignatureTaker.into())
+        );
+        complete_trade(&id(), &account_infos(&mut accounts, 1, true)).unwrap();
+        assert_eq!(token_amount(&accounts[3]), trade.taker_amount);
+    }
+
+    // Accounts for a CancelTrade by `trade`'s maker, in instruction order, with
+    // `trade` stored in the first and its escrow funded
+    fn cancel_trade_accounts(trade: &Trade) -> Vec<TestAccount> {
+        let (authority, _) = escrow_authority(&id());
+        let mut trade_data = vec![0; Trade::LEN];
+        Trade::pack(*trade, &mut trade_data).unwrap();
+        vec![
+            TestAccount::new(id(), trade_data),
+            TestAccount::with_key(trade.maker_pubkey, Pubkey::default(), vec![]),
+            TestAccount::with_key(
+                trade.escrow_pubkey,
+                spl_token::id(),
+                token_account(trade.maker_token_pubkey, authority, trade.maker_amount),
+            ),
+            TestAccount::with_key(authority, Pubkey::default(), vec![]),
+            TestAccount::new(spl_token::id(), token_account(trade.maker_token_pubkey, trade.maker_pubkey, 0)),
+            TestAccount::with_key(trade.maker_token_pubkey, spl_token::id(), mint_account(trade.maker_decimals)),
+            TestAccount::with_key(spl_token::id(), Pubkey::default(), vec![]),
+        ]
+    }
+
+    fn update_params(maker_amount: u64) -> UpdateTradeParams {
+        UpdateTradeParams {
+            taker_amount: 1_200,
+            maker_amount,
+            expiry: None,
+            alternative_payments: vec![],
+        }
+    }
+
+    #[test]
+    fn update_trade_reprices_and_refunds_a_smaller_trade() {
+        install_test_syscalls();
+        let trade = open_trade();
+        // UpdateTrade takes CancelTrade's accounts, then the alternative mints
+        let mut accounts = cancel_trade_accounts(&trade);
+        let alternative = PaymentParams {
+            mint: Pubkey::new_unique(),
+            amount: 900,
+        };
+        accounts.push(TestAccount::with_key(alternative.mint, spl_token::id(), mint_account(8)));
+        let params = UpdateTradeParams {
+            expiry: Some((ExpiryKind::UnixTimestamp, 1_800_000_000)),
+            alternative_payments: vec![alternative],
+            ..update_params(1_500)
+        };
+        update_trade(&id(), &account_infos(&mut accounts, 1, true), params).unwrap();
+
+        let updated = Trade::unpack(&accounts[0].data).unwrap();
+        assert_eq!(updated.status(), TradeStatus::Open);
+        assert_eq!((updated.taker_amount, updated.maker_amount), (1_200, 1_500));
+        assert_eq!((updated.expiry_kind(), updated.expiry), (ExpiryKind::UnixTimestamp, 1_800_000_000));
+        assert_eq!(
+            updated.alternative_payments(),
+            &[PaymentOption {
+                mint: alternative.mint,
+                amount: alternative.amount,
+                decimals: 8,
+            }]
+        );
+        assert_eq!(token_amount(&accounts[2]), 1_500);
+        assert_eq!(token_amount(&accounts[4]), 500);
+    }
+
+    #[test]
+    fn update_trade_keeps_the_size_and_expiry_unless_asked() {
+        install_test_syscalls();
+        let trade = open_trade();
+        let mut accounts = cancel_trade_accounts(&trade);
+        update_trade(&id(), &account_infos(&mut accounts, 1, true), update_params(trade.maker_amount)).unwrap();
+
+        let updated = Trade::unpack(&accounts[0].data).unwrap();
+        assert_eq!(updated.taker_amount, 1_200);
+        assert_eq!((updated.expiry_kind(), updated.expiry), (trade.expiry_kind(), trade.expiry));
+        assert_eq!(token_amount(&accounts[2]), trade.maker_amount);
+        assert_eq!(token_amount(&accounts[4]), 0);
+    }
+
+    #[test]
+    fn update_trade_cannot_grow_past_the_escrow() {
+        install_test_syscalls();
+        let trade = open_trade();
+        let mut accounts = cancel_trade_accounts(&trade);
+        assert_eq!(
+            update_trade(&id(), &account_infos(&mut accounts, 1, true), update_params(trade.maker_amount + 1)),
+            Err(DexError::InsufficientFunds.into())
+        );
+
+        // The escrow lost tokens the trade is still owed
+        let (authority, _) = escrow_authority(&id());
+        accounts[2].data = token_account(trade.maker_token_pubkey, authority, 1_000);
+        assert_eq!(
+            update_trade(&id(), &account_infos(&mut accounts, 1, true), update_params(1_500)),
+            Err(DexError::InsufficientFunds.into())
+        );
+        assert_eq!(Trade::unpack(&accounts[0].data).unwrap(), trade);
+    }
+
+    #[test]
+    fn update_trade_rejects_expiries() {
+        install_test_syscalls();
+        let trade = open_trade();
+        let mut accounts = cancel_trade_accounts(&trade);
+        let cases = [
+            ((ExpiryKind::UnixTimestamp, u64::MAX), DexError::InvalidExpiry),
+            // A new expiry that has already passed
+            ((ExpiryKind::Slot, test_clock().slot), DexError::TradeExpired),
+        ];
+        for (expiry, error) in cases {
+            let params = UpdateTradeParams {
+                expiry: Some(expiry),
+                ..update_params(1_500)
+            };
+            assert_eq!(
+                update_trade(&id(), &account_infos(&mut accounts, 1, true), params),
+                Err(error.into())
+            );
+        }
+
+        // A trade that has already expired can't be extended
+        let mut trade = open_trade();
+        trade.set_expiry(ExpiryKind::Slot, test_clock().slot);
+        let mut accounts = cancel_trade_accounts(&trade);
+        let params = UpdateTradeParams {
+            expiry: Some((ExpiryKind::Never, 0)),
+            ..update_params(1_500)
+        };
+        assert_eq!(
+            update_trade(&id(), &account_infos(&mut accounts, 1, true), params),
+            Err(DexError::TradeExpired.into())
+        );
+    }
+
+    #[test]
+    fn cancel_trade_refunds_the_maker() {
+        install_test_syscalls();
+        let trade = open_trade();
+        let mut accounts = cancel_trade_accounts(&trade);
+        assert_eq!(
+            cancel_trade(&id(), &account_infos(&mut accounts, 1, false)),
+            Err(DexError::MissingRequiredSignatureMaker.into())
+        );
+        cancel_trade(&id(), &account_infos(&mut accounts, 1, true)).unwrap();
+        assert_eq!(Trade::unpack(&accounts[0].data).unwrap().status(), TradeStatus::Cancelled);
+        assert_eq!(token_amount(&accounts[2]), 0);
+        assert_eq!(token_amount(&accounts[4]), trade.maker_amount);
+
+        assert_eq!(
+            cancel_trade(&id(), &account_infos(&mut accounts, 1, true)),
+            Err(DexError::InvalidStatusTransition.into())
+        );
+    }
+
+    #[test]
+    fn cancelling_an_expired_trade_records_it_as_expired() {
+        install_test_syscalls();
+        let mut trade = open_trade();
+        trade.set_expiry(ExpiryKind::Slot, test_clock().slot);
+        let mut accounts = cancel_trade_accounts(&trade);
+        cancel_trade(&id(), &account_infos(&mut accounts, 1, true)).unwrap();
+        assert_eq!(Trade::unpack(&accounts[0].data).unwrap().status(), TradeStatus::Expired);
+        assert_eq!(token_amount(&accounts[4]), trade.maker_amount);
+    }
+
+    #[test]
+    fn version_components_parse_at_compile_time() {
+        const PARSED: [u16; 4] = [
+            parse_version_part("0"),
+            parse_version_part("7"),
+            parse_version_part("120"),
+            parse_version_part("65535"),
+        ];
+        assert_eq!(PARSED, [0, 7, 120, 65_535]);
+        assert_eq!(ProgramVersion::current(), ProgramVersion::CURRENT);
+    }
+
+    #[test]
+    #[should_panic]
+    fn version_components_must_fit_a_u16() {
+        parse_version_part(std::hint::black_box("65536"));
+    }
+
+    #[test]
+    fn version_compatibility_follows_semver() {
+        let version = |major, minor, patch| ProgramVersion { major, minor, patch };
+        let cases = [
+            (version(1, 2, 3), version(1, 0, 0), true),
+            (version(1, 2, 3), version(1, 9, 9), true),
+            (version(1, 2, 3), version(2, 2, 3), false),
+            (version(2, 0, 0), version(1, 0, 0), false),
+            // Below 1.0 every minor is a breaking release
+            (version(0, 2, 3), version(0, 2, 0), true),
+            (version(0, 2, 3), version(0, 3, 0), false),
+            (version(0, 2, 3), version(1, 2, 3), false),
+            // and below 0.1 every patch
+            (version(0, 0, 3), version(0, 0, 3), true),
+            (version(0, 0, 3), version(0, 0, 4), false),
+        ];
+        for (program, client, compatible) in cases {
+            assert_eq!(
+                program.is_compatible_with(&client),
+                compatible,
+                "{:?} with {:?}",
+                program,
+                client
+            );
+        }
+    }
+
+    #[test]
+    fn json_log_values_are_escaped() {
+        assert_eq!(json_escape("plain 123"), "plain 123");
+        assert_eq!(json_escape(r#"a "quoted" \ path"#), r#"a \"quoted\" \\ path"#);
+        assert_eq!(json_escape("line\nbreak\ttab"), r"line\nbreak\ttab");
+        assert_eq!(json_escape("\u{0}\u{1f}"), r"\u0000\u001f");
+    }
+
+    #[test]
+    fn trade_layout_round_trips() {
+        let trade = open_trade();
+        let mut data = vec![0; Trade::LEN];
+        Trade::pack(trade, &mut data).unwrap();
+        assert_eq!(data[TRADE_STATUS_OFFSET], TradeStatus::Open as u8);
+        assert_eq!(data[TRADE_LAYOUT_VERSION_OFFSET], TRADE_LAYOUT_VERSION);
+        assert_eq!(borsh::to_vec(&trade).unwrap().len(), Trade::LEN);
+        assert_eq!(Trade::unpack(&data).unwrap(), trade);
+    }
+
+    #[test]
+    fn zeroed_trade_account_is_uninitialized() {
+        let trade = Trade::unpack_unchecked(&[0; Trade::LEN]).unwrap();
+        assert!(!trade.is_initialized());
+    }
+
+    #[test]
+    fn unknown_trade_layouts_are_rejected() {
+        let mut data = vec![0; Trade::LEN];
+        Trade::pack(open_trade(), &mut data).unwrap();
+        data[TRADE_LAYOUT_VERSION_OFFSET] = TRADE_LAYOUT_VERSION + 1;
+        assert_eq!(
+            Trade::unpack(&data),
+            Err(DexError::UnsupportedTradeLayout.into())
+        );
+    }
+
+    // The pre-versioning `Trade` as that build stored it, for maker 0x11..,
+    // taker token 0x22.., maker token 0x33.., taker_amount 1_000, maker_amount
+    // 2_000 and timestamp 1_700_000_000. Taken from the baseline struct's
+    // in-memory bytes, which is what its `Pack` impl copied into the account.
+    fn legacy_trade_data() -> Vec<u8> {
+        let mut data = Vec::with_capacity(LEGACY_TRADE_LEN);
+        data.extend_from_slice(&[0x11; 32]);
+        data.extend_from_slice(&[0x22; 32]);
+        data.extend_from_slice(&[0x33; 32]);
+        data.extend_from_slice(&[232, 3, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[208, 7, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0, 241, 83, 101, 0, 0, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn legacy_trades_decode_from_the_deployed_layout() {
+        let trade = Trade::load(&legacy_trade_data()).unwrap();
+        assert!(trade.is_legacy());
+        assert_eq!(trade.status(), TradeStatus::Open);
+        assert_eq!(trade.maker_pubkey, Pubkey::new_from_array([0x11; 32]));
+        assert_eq!(trade.taker_token_pubkey, Pubkey::new_from_array([0x22; 32]));
+        assert_eq!(trade.maker_token_pubkey, Pubkey::new_from_array([0x33; 32]));
+        assert_eq!((trade.taker_amount, trade.maker_amount), (1_000, 2_000));
+        assert_eq!(trade.trade_timestamp, 1_700_000_000);
+        assert_eq!(trade.expiry_kind(), ExpiryKind::Never);
+        assert!(trade.alternative_payments().is_empty());
+
+        assert!(!Trade::load(&[0; LEGACY_TRADE_LEN]).unwrap().is_initialized());
+    }
+
+    #[test]
+    fn legacy_trades_can_be_settled_but_not_repriced() {
+        install_test_syscalls();
+        let mut trade = Trade::load(&legacy_trade_data()).unwrap();
+        trade.maker_decimals = 9;
+        // Any escrow the program holds in the maker mint
+        trade.escrow_pubkey = Pubkey::new_unique();
+        let mut accounts = cancel_trade_accounts(&trade);
+        accounts[0].data = legacy_trade_data();
+
+        let params = UpdateTradeParams {
+            taker_amount: 1_000,
+            maker_amount: 1_500,
+            expiry: None,
+            alternative_payments: vec![],
+        };
+        assert_eq!(
+            update_trade(&id(), &account_infos(&mut accounts, 1, true), params),
+            Err(DexError::UnsupportedTradeLayout.into())
+        );
+
+        cancel_trade(&id(), &account_infos(&mut accounts, 1, true)).unwrap();
+        assert_eq!(accounts[0].data, vec![0; LEGACY_TRADE_LEN]);
+        assert_eq!(token_amount(&accounts[4]), 2_000);
+
+        // Filling prices the receipt off the mints
+        let mut accounts = complete_trade_accounts(&trade, trade.taker_token_pubkey, 6);
+        accounts[0].data = legacy_trade_data();
+        complete_trade(&id(), &account_infos(&mut accounts, 1, true)).unwrap();
+        assert_eq!(accounts[0].data, vec![0; LEGACY_TRADE_LEN]);
+        assert_eq!(token_amount(&accounts[6]), 2_000);
+        let receipt = RETURN_DATA.with(|data| FillReceipt::try_from_slice(&data.borrow()).unwrap());
+        // 2_000 raw at 9 decimals for 1_000 raw at 6 decimals
+        assert_eq!(receipt.price, Some(500_000_000_000));
+    }
+}